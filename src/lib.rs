@@ -1,34 +1,74 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
+use std::str::FromStr;
 
-use web_sys::{Event, FocusEvent, HtmlElement};
+use wasm_bindgen::JsCast;
+use web_sys::{Event, FocusEvent, HtmlElement, HtmlInputElement};
 use yew::services::reader::{File, FileData, ReaderService, ReaderTask};
 use yew::services::Task;
 use yew::{
-    html, Callback, ChangeData, Component, ComponentLink, Html, InputData, NodeRef, Properties,
-    ShouldRender,
+    html, Callback, ChangeData, Component, ComponentLink, Html, InputData, MouseEvent, NodeRef,
+    Properties, ShouldRender,
 };
-use yew_state::{SharedHandle, SharedState, SharedStateComponent};
+use yew_state::{SharedHandle, SharedState, SharedStateComponent, StateHandler, StorageHandle};
 
-type ViewForm<T> = Rc<dyn Fn(FormHandle<T>) -> Html>;
+type ViewForm<T, H = SharedHandle<T>> = Rc<dyn Fn(FormHandle<T, H>) -> Html>;
 
-pub struct FormHandle<'a, T>
+/// Field name -> list of error messages for that field.
+pub type ValidationErrors = HashMap<String, Vec<String>>;
+
+static NO_ERRORS: &[String] = &[];
+
+/// Outcome of an [`Props::on_submit_async`] future.
+pub enum SubmitResult {
+    Ok,
+    Err(ValidationErrors),
+}
+
+pub struct FormHandle<'a, T, H = SharedHandle<T>>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T>,
 {
-    handle: &'a SharedHandle<T>,
-    link: &'a ComponentLink<Model<T>>,
+    handle: &'a H,
+    link: &'a ComponentLink<Model<T, H>>,
     ref_form: &'a NodeRef,
+    errors: &'a ValidationErrors,
+    submitting: bool,
 }
 
-impl<'a, T> FormHandle<'a, T>
+impl<'a, T, H> FormHandle<'a, T, H>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T>,
 {
     /// Current form state.
     pub fn state(&self) -> &T {
         self.handle.state()
     }
 
+    /// Errors from the last failed validation, keyed by field name.
+    pub fn errors(&self) -> &ValidationErrors {
+        self.errors
+    }
+
+    /// Errors for a single field, or an empty slice if it has none.
+    pub fn field_errors(&self, name: &str) -> &[String] {
+        self.errors
+            .get(name)
+            .map(Vec::as_slice)
+            .unwrap_or(NO_ERRORS)
+    }
+
+    /// Whether an `on_submit_async` future from a previous submit is still
+    /// in flight. Useful for disabling the submit button and showing a
+    /// spinner.
+    pub fn is_submitting(&self) -> bool {
+        self.submitting
+    }
+
     /// Callback for submitting the form.
     pub fn submit<E: 'static>(&self) -> Callback<E> {
         let node = self.ref_form.clone();
@@ -52,20 +92,32 @@ where
     }
 
     /// Callback for setting state from `InputData`.
-    pub fn set_text(&self, f: impl FnOnce(&mut T, String) + 'static) -> Callback<InputData> {
-        self.handle
-            .reduce_callback_once_with(f)
+    ///
+    /// If `field` is given, that field's errors are cleared as soon as the
+    /// user changes the input, so stale messages don't linger.
+    pub fn set_text(
+        &self,
+        field: Option<&'static str>,
+        f: impl FnOnce(&mut T, String) + 'static,
+    ) -> Callback<InputData> {
+        self.with_clear_errors(field, self.handle.reduce_callback_once_with(f))
             .reform(|data: InputData| data.value)
     }
 
     /// Callback for setting state from select elements.
     ///
+    /// If `field` is given, that field's errors are cleared as soon as the
+    /// user changes the selection, so stale messages don't linger.
+    ///
     /// # Panics
     ///
     /// Panics if used on anything other than a select element.
-    pub fn set_select(&self, f: impl FnOnce(&mut T, String) + 'static) -> Callback<ChangeData> {
-        self.handle
-            .reduce_callback_once_with(f)
+    pub fn set_select(
+        &self,
+        field: Option<&'static str>,
+        f: impl FnOnce(&mut T, String) + 'static,
+    ) -> Callback<ChangeData> {
+        self.with_clear_errors(field, self.handle.reduce_callback_once_with(f))
             .reform(|data: ChangeData| {
                 if let ChangeData::Select(el) = data {
                     el.value()
@@ -75,6 +127,95 @@ where
             })
     }
 
+    /// Callback for setting state from numeric `<input>` elements (e.g.
+    /// `type="number"`). Intermediate input that doesn't parse as `N` (such
+    /// as a momentarily empty field) is silently ignored rather than
+    /// calling the reducer with garbage.
+    ///
+    /// If `field` is given, that field's errors are cleared as soon as the
+    /// user changes the input, so stale messages don't linger.
+    pub fn set_number<N>(
+        &self,
+        field: Option<&'static str>,
+        f: impl FnOnce(&mut T, N) + 'static,
+    ) -> Callback<InputData>
+    where
+        N: FromStr,
+    {
+        self.with_clear_errors(field, self.set_parsed(f))
+    }
+
+    /// Callback for setting state from `<input type="range">` sliders. Like
+    /// [`set_number`](Self::set_number), unparseable intermediate input is
+    /// silently ignored.
+    ///
+    /// If `field` is given, that field's errors are cleared as soon as the
+    /// user changes the input, so stale messages don't linger.
+    pub fn set_range<N>(
+        &self,
+        field: Option<&'static str>,
+        f: impl FnOnce(&mut T, N) + 'static,
+    ) -> Callback<InputData>
+    where
+        N: FromStr,
+    {
+        self.with_clear_errors(field, self.set_parsed(f))
+    }
+
+    /// Shared implementation of [`set_number`](Self::set_number) and
+    /// [`set_range`](Self::set_range): parses `InputData::value` as `N`,
+    /// silently ignoring input that doesn't parse.
+    fn set_parsed<N>(&self, f: impl FnOnce(&mut T, N) + 'static) -> Callback<InputData>
+    where
+        N: FromStr,
+    {
+        let set = self.handle.reduce_callback_once_with(f);
+        Callback::from(move |data: InputData| {
+            if let Ok(value) = data.value.parse() {
+                set.emit(value);
+            }
+        })
+    }
+
+    /// Callback for setting state from `<input type="checkbox">` elements.
+    /// Bound via `onclick` rather than `onchange` so the callback gets a
+    /// `MouseEvent` whose `target()` is exactly the checkbox that was
+    /// clicked; reading `HtmlInputElement::checked()` off that target is
+    /// reliable cross-browser, unlike `document.active_element()` (Safari
+    /// doesn't focus a checkbox on click).
+    ///
+    /// If `field` is given, that field's errors are cleared as soon as the
+    /// user changes the checkbox, so stale messages don't linger.
+    pub fn set_checkbox(
+        &self,
+        field: Option<&'static str>,
+        f: impl FnOnce(&mut T, bool) + 'static,
+    ) -> Callback<MouseEvent> {
+        let set = self.handle.reduce_callback_once_with(f).reform(|e: MouseEvent| {
+            e.target()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+                .map(|el| el.checked())
+                .unwrap_or(false)
+        });
+        self.with_clear_errors(field, set)
+    }
+
+    /// Wraps `cb` so that, when `field` is `Some`, emitting it also clears
+    /// that field's stored validation errors.
+    fn with_clear_errors<E: 'static>(
+        &self,
+        field: Option<&'static str>,
+        cb: Callback<E>,
+    ) -> Callback<E> {
+        match field {
+            Some(name) => self.link.callback(move |e: E| {
+                cb.emit(e);
+                Msg::ClearFieldError(name)
+            }),
+            None => cb,
+        }
+    }
+
     /// Callback for setting files
     pub fn set_file(
         &self,
@@ -82,41 +223,71 @@ where
     ) -> Callback<ChangeData> {
         let set_files = self.set_with(f);
         self.link.callback(move |data| {
-            let mut result = Vec::new();
-            if let ChangeData::Files(files) = data {
-                let files = js_sys::try_iter(&files)
-                    .unwrap()
-                    .unwrap()
-                    .into_iter()
-                    .map(|v| File::from(v.unwrap()));
-                result.extend(files);
-            }
-            Msg::Files(result, set_files.clone())
+            Msg::Files(files_from_change_data(data), set_files.clone())
+        })
+    }
+
+    /// Callback for reading selected files as base64 data URLs, ready to
+    /// drop straight into an `<img src=...>` for previews.
+    pub fn set_file_data_url(
+        &self,
+        f: impl FnOnce(&mut T, String) + Copy + 'static,
+    ) -> Callback<ChangeData> {
+        let set_url = self.set_with(f);
+        self.link.callback(move |data| {
+            Msg::FileDataUrl(files_from_change_data(data), set_url.clone())
         })
     }
 }
 
+/// Extracts the selected files from `data`, or an empty `Vec` if it isn't
+/// the `Files` variant.
+fn files_from_change_data(data: ChangeData) -> Vec<File> {
+    let mut result = Vec::new();
+    if let ChangeData::Files(files) = data {
+        let files = js_sys::try_iter(&files)
+            .unwrap()
+            .unwrap()
+            .into_iter()
+            .map(|v| File::from(v.unwrap()));
+        result.extend(files);
+    }
+    result
+}
+
 #[derive(Properties, Clone)]
-pub struct Props<T>
+pub struct Props<T, H = SharedHandle<T>>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T>,
 {
     #[prop_or_default]
-    handle: SharedHandle<T>,
+    handle: H,
     #[prop_or_default]
     pub on_submit: Callback<T>,
     #[prop_or_default]
     pub default: Option<T>,
     #[prop_or_default]
     pub auto_reset: bool,
-    pub view: ViewForm<T>,
+    /// Run against form state on submit; returning `Err` blocks the submit
+    /// and stores the errors instead of calling `on_submit`.
+    #[prop_or_default]
+    pub validate: Option<Rc<dyn Fn(&T) -> Result<(), ValidationErrors>>>,
+    /// Async alternative to `on_submit`. When set, submit drives this
+    /// future instead of emitting `on_submit` synchronously, and the reset/
+    /// `auto_reset` path only runs once it resolves successfully.
+    #[prop_or_default]
+    pub on_submit_async:
+        Option<Rc<dyn Fn(T) -> Pin<Box<dyn Future<Output = SubmitResult> + 'static>>>>,
+    pub view: ViewForm<T, H>,
 }
 
-impl<T> SharedState for Props<T>
+impl<T, H> SharedState for Props<T, H>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T>,
 {
-    type Handle = SharedHandle<T>;
+    type Handle = H;
 
     fn handle(&mut self) -> &mut Self::Handle {
         &mut self.handle
@@ -125,28 +296,36 @@ where
 
 pub enum Msg {
     Files(Vec<File>, Callback<FileData>),
+    FileDataUrl(Vec<File>, Callback<String>),
     Submit(FocusEvent),
+    SubmitComplete(SubmitResult),
+    Validated(ValidationErrors),
+    ClearFieldError(&'static str),
 }
 
-pub struct Model<T>
+pub struct Model<T, H = SharedHandle<T>>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T>,
 {
-    props: Props<T>,
+    props: Props<T, H>,
     cb_submit: Callback<FocusEvent>,
     cb_reset: Callback<()>,
     link: ComponentLink<Self>,
     file_reader: ReaderService,
     tasks: Vec<ReaderTask>,
     ref_form: NodeRef,
+    errors: ValidationErrors,
+    submitting: bool,
 }
 
-impl<T> Component for Model<T>
+impl<T, H> Component for Model<T, H>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T> + 'static,
 {
     type Message = Msg;
-    type Properties = Props<T>;
+    type Properties = Props<T, H>;
 
     fn create(props: Self::Properties, link: ComponentLink<Self>) -> Self {
         let cb_submit = link.callback(|e: FocusEvent| {
@@ -161,6 +340,8 @@ where
             tasks: Default::default(),
             file_reader: Default::default(),
             ref_form: Default::default(),
+            errors: Default::default(),
+            submitting: false,
         };
         this.update_default();
         this
@@ -169,16 +350,76 @@ where
     fn update(&mut self, msg: Self::Message) -> ShouldRender {
         match msg {
             Msg::Submit(e) => {
-                self.props.on_submit.emit(self.props.handle.state().clone());
+                if self.submitting {
+                    return false;
+                }
+
+                let state = self.props.handle.state();
+                if let Some(errors) = self
+                    .props
+                    .validate
+                    .as_ref()
+                    .and_then(|validate| validate(state).err())
+                {
+                    self.link.send_message(Msg::Validated(errors));
+                    return false;
+                }
+
+                self.errors.clear();
+
+                if let Some(on_submit_async) = self.props.on_submit_async.clone() {
+                    self.submitting = true;
+                    let future = on_submit_async(state.clone());
+                    self.link
+                        .send_future(async move { Msg::SubmitComplete(future.await) });
+                    return true;
+                }
+
+                self.props.on_submit.emit(state.clone());
                 if self.props.auto_reset {
                     // Clear form
                     let reset_event = Event::new("reset").unwrap();
                     e.target()
                         .map(|target| target.dispatch_event(&reset_event).ok());
-                    // Reset state
-                    self.cb_reset.emit(());
+                    // Reset state, writing through so a persisted draft is cleared too
+                    self.reset_and_clear_persisted();
+                }
+                // Always re-render: a prior failed submit may have left
+                // stale errors on screen that `self.errors.clear()` above
+                // just cleared internally.
+                true
+            }
+            Msg::SubmitComplete(result) => {
+                self.submitting = false;
+                match result {
+                    SubmitResult::Ok => {
+                        self.errors.clear();
+                        if self.props.auto_reset {
+                            // Clear form
+                            if let Some(form) = self.ref_form.cast::<HtmlElement>() {
+                                let reset_event = Event::new("reset").unwrap();
+                                let _ = form.dispatch_event(&reset_event);
+                            }
+                            // Reset state, writing through so a persisted draft is cleared too
+                            self.reset_and_clear_persisted();
+                        }
+                    }
+                    SubmitResult::Err(errors) => {
+                        self.errors = errors;
+                    }
+                }
+                true
+            }
+            Msg::Validated(errors) => {
+                self.errors = errors;
+                true
+            }
+            Msg::ClearFieldError(name) => {
+                if self.errors.remove(name).is_some() {
+                    true
+                } else {
+                    false
                 }
-                false
             }
             Msg::Files(files, cb) => {
                 self.tasks.retain(Task::is_active);
@@ -192,6 +433,24 @@ where
                 }
                 false
             }
+            Msg::FileDataUrl(files, cb) => {
+                self.tasks.retain(Task::is_active);
+                for file in files.into_iter() {
+                    let mime = file.type_();
+                    let cb = cb.clone();
+                    let on_read = Callback::from(move |data: FileData| {
+                        let encoded = base64::encode(&data.content);
+                        cb.emit(format!("data:{};base64,{}", mime, encoded));
+                    });
+                    let task = self
+                        .file_reader
+                        .read_file(file, on_read)
+                        .expect("Error reading file");
+
+                    self.tasks.push(task);
+                }
+                false
+            }
         }
     }
 
@@ -200,6 +459,8 @@ where
             handle: &self.props.handle,
             link: &self.link,
             ref_form: &self.ref_form,
+            errors: &self.errors,
+            submitting: self.submitting,
         };
         html! {
             <form
@@ -221,9 +482,10 @@ where
     }
 }
 
-impl<T> Model<T>
+impl<T, H> Model<T, H>
 where
     T: PartialEq + Default + Clone + 'static,
+    H: StateHandler<T> + 'static,
 {
     fn update_default(&mut self) {
         let default = self
@@ -243,13 +505,44 @@ where
             self.cb_reset.emit(());
         }
     }
+
+    /// Resets state to the default the same way `cb_reset` does, but
+    /// through a freshly built `reduce_callback_once` rather than the
+    /// cached `cb_reset`.
+    ///
+    /// This writes the cleared value through the handle the same way as
+    /// every other state-changing setter in this file (`set_text`,
+    /// `set_number`, ...), which are all built on
+    /// `reduce_callback_once`/`reduce_callback_once_with`, so with a
+    /// `StorageHandle` the stored draft is replaced by the default. It is
+    /// called on auto-reset so a submitted `StorageForm` doesn't
+    /// repopulate the stale draft from storage.
+    fn reset_and_clear_persisted(&mut self) {
+        let default = self
+            .props
+            .default
+            .as_ref()
+            .map(Clone::clone)
+            .unwrap_or_default();
+
+        self.props
+            .handle()
+            .reduce_callback_once(move |state| *state = default)
+            .emit(());
+    }
 }
 
 pub struct FormScope;
 pub type Form<T, SCOPE = FormScope> = SharedStateComponent<Model<T>, SCOPE>;
 
-pub fn view_form<T: PartialEq + Default + Clone>(
-    f: impl Fn(FormHandle<T>) -> Html + 'static,
-) -> ViewForm<T> {
+/// Auto-saving variant of [`Form`] that persists a draft of form state to
+/// storage on every change and restores it across reloads, via
+/// [`StorageHandle`].
+pub type StorageForm<T, SCOPE = FormScope> =
+    SharedStateComponent<Model<T, StorageHandle<T>>, SCOPE>;
+
+pub fn view_form<T: PartialEq + Default + Clone, H: StateHandler<T>>(
+    f: impl Fn(FormHandle<T, H>) -> Html + 'static,
+) -> ViewForm<T, H> {
     Rc::new(f)
 }